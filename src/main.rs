@@ -1,7 +1,11 @@
 // #![allow(unused, dead_code)]
 // #![deny(unused_must_use)]
 
-use std::{collections::HashMap, time::Duration};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    hash::{Hash, Hasher},
+    time::Duration,
+};
 
 use bevy::prelude::*;
 use bevy_egui::{
@@ -10,9 +14,8 @@ use bevy_egui::{
 };
 use egui_snarl::{
     ui::{PinInfo, SnarlStyle, SnarlViewer},
-    InPinId, Snarl,
+    InPinId, NodeId, OutPinId, Snarl,
 };
-use recursive::recursive;
 use strum::IntoEnumIterator;
 
 fn main() {
@@ -30,17 +33,34 @@ fn setup(mut commands: Commands) {
     let simulation_tick = Simulation {
         timer: Timer::from_seconds(0.1, TimerMode::Repeating),
         ticks: 0,
+        probes: HashSet::new(),
+        history: HashMap::new(),
+        history_depth: DEFAULT_HISTORY_DEPTH,
+        zoom: 1.0,
+        scroll: 0,
     };
     commands.insert_resource(simulation_tick);
     commands.insert_resource(graph);
 }
 
 fn ui(mut contexts: EguiContexts, mut graph: ResMut<Graph>, mut simulation: ResMut<Simulation>) {
+    graph.diagnostics = diagnose(&graph.state, &graph.oscillating);
+
     if let Some(ctx) = contexts.try_ctx_mut() {
         egui::CentralPanel::default().show(ctx, |ui| {
-            graph
-                .state
-                .show(&mut GraphViewer, &SnarlStyle::default(), "snarl", ui);
+            let Graph {
+                state,
+                chips,
+                selection,
+                chip_name,
+                ..
+            } = &mut *graph;
+            let mut viewer = GraphViewer {
+                chips,
+                selection,
+                chip_name,
+            };
+            state.show(&mut viewer, &SnarlStyle::default(), "snarl", ui);
         });
         egui::Window::new("Controls").show(ctx, |ui| {
             // slider for the simulation speed
@@ -71,7 +91,84 @@ fn ui(mut contexts: EguiContexts, mut graph: ResMut<Graph>, mut simulation: ResM
                         }));
                 }
             });
+
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                ui.label("Runner");
+                egui::ComboBox::from_id_source("runner_kind")
+                    .selected_text(graph.runner.to_string())
+                    .show_ui(ui, |ui| {
+                        for kind in RunnerKind::iter() {
+                            ui.selectable_value(&mut graph.runner, kind, kind.to_string());
+                        }
+                    });
+            });
+
+            ui.separator();
+
+            ui.horizontal(|ui| {
+                if ui.button("Save").clicked() {
+                    graph.save(SAVE_PATH);
+                }
+                if ui.button("Load").clicked() {
+                    graph.load(SAVE_PATH);
+                }
+            });
+
+            ui.separator();
+
+            egui::CollapsingHeader::new("Diagnostics").show(ui, |ui| {
+                if graph.runner != RunnerKind::Synchronous {
+                    ui.colored_label(
+                        egui::Color32::YELLOW,
+                        "Combinational cycle detection requires the Synchronous runner",
+                    );
+                }
+
+                let diagnostics = graph.diagnostics.clone();
+                if diagnostics.is_empty() {
+                    ui.label("No issues found");
+                }
+                for diagnostic in diagnostics {
+                    let (icon, color) = match diagnostic.severity {
+                        Severity::Info => ("ℹ", egui::Color32::LIGHT_BLUE),
+                        Severity::Warning => ("⚠", egui::Color32::YELLOW),
+                        Severity::Error => ("⛔", egui::Color32::RED),
+                    };
+                    let label = format!("{icon} {}", diagnostic.message);
+                    if ui.colored_label(color, label).clicked() {
+                        graph.selection.clear();
+                        graph.selection.insert(diagnostic.node);
+                    }
+                }
+            });
+
+            egui::CollapsingHeader::new("Selection").show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    ui.label("Chip name");
+                    ui.text_edit_singleline(&mut graph.chip_name);
+                });
+
+                let nodes = graph
+                    .state
+                    .node_ids()
+                    .map(|(id, node)| (id, node.display_name()))
+                    .collect::<Vec<_>>();
+                for (id, name) in nodes {
+                    let mut selected = graph.selection.contains(&id);
+                    if ui.checkbox(&mut selected, name).changed() {
+                        if selected {
+                            graph.selection.insert(id);
+                        } else {
+                            graph.selection.remove(&id);
+                        }
+                    }
+                }
+            });
         });
+
+        waveform_window(ctx, &graph, &mut simulation);
     }
 }
 
@@ -79,20 +176,243 @@ fn tick(mut graph: ResMut<Graph>, time: Res<Time>, mut simulation: ResMut<Simula
     graph.tick(&mut simulation, time.delta());
 }
 
+/// A logic-analyzer style window: pick probes, then scrub/zoom through their
+/// recorded history as stacked square-wave traces.
+fn waveform_window(ctx: &egui::Context, graph: &Graph, simulation: &mut Simulation) {
+    const ROW_HEIGHT: f32 = 28.0;
+
+    egui::Window::new("Waveform").show(ctx, |ui| {
+        ui.horizontal(|ui| {
+            ui.label("History depth");
+            ui.add(egui::DragValue::new(&mut simulation.history_depth).range(8..=4096));
+            ui.label("Zoom");
+            ui.add(egui::Slider::new(&mut simulation.zoom, 0.1..=4.0));
+            ui.label("Scroll");
+            ui.add(egui::Slider::new(
+                &mut simulation.scroll,
+                0..=simulation.history_depth,
+            ));
+        });
+
+        ui.separator();
+
+        egui::CollapsingHeader::new("Probes").show(ui, |ui| {
+            let probeable = graph
+                .state
+                .node_ids()
+                .filter(|(_, node)| node.is_probeable())
+                .map(|(id, node)| (id, node.display_name()))
+                .collect::<Vec<_>>();
+            for (id, name) in probeable {
+                let mut probed = simulation.probes.contains(&id);
+                if ui.checkbox(&mut probed, name).changed() {
+                    if probed {
+                        simulation.probes.insert(id);
+                    } else {
+                        simulation.probes.remove(&id);
+                        simulation.history.remove(&id);
+                    }
+                }
+            }
+        });
+
+        ui.separator();
+
+        let zoom = simulation.zoom.max(0.01);
+        let scroll = simulation.scroll;
+
+        // `simulation.history` is a `HashMap`, so its own iteration order
+        // reshuffles every frame; walk the graph's (stable) node order
+        // instead so a given signal always lands on the same row.
+        let probed_ids = graph
+            .state
+            .node_ids()
+            .map(|(id, _)| id)
+            .filter(|id| simulation.history.contains_key(id))
+            .collect::<Vec<_>>();
+
+        for id in probed_ids {
+            let history = &simulation.history[&id];
+            let name = graph
+                .state
+                .get_node(id)
+                .map(Node::display_name)
+                .unwrap_or_default();
+            ui.label(name);
+
+            let (rect, _response) =
+                ui.allocate_exact_size(egui::vec2(ui.available_width(), ROW_HEIGHT), egui::Sense::hover());
+            let painter = ui.painter_at(rect);
+            painter.rect_filled(rect, 0.0, egui::Color32::from_gray(20));
+
+            let visible_samples = ((rect.width() / zoom).ceil() as usize).max(1);
+            let end = history.len().saturating_sub(scroll);
+            let start = end.saturating_sub(visible_samples);
+            let samples = history.iter().skip(start).take(end - start).copied().collect::<Vec<_>>();
+
+            if samples.is_empty() {
+                continue;
+            }
+
+            let high_y = rect.top() + ROW_HEIGHT * 0.2;
+            let low_y = rect.top() + ROW_HEIGHT * 0.8;
+            let step_x = rect.width() / samples.len() as f32;
+
+            let mut points = Vec::with_capacity(samples.len() * 2);
+            for (i, &value) in samples.iter().enumerate() {
+                let x = rect.left() + i as f32 * step_x;
+                let y = if value { high_y } else { low_y };
+                if let Some(last) = points.last().copied() {
+                    let egui::Pos2 { y: last_y, .. } = last;
+                    if last_y != y {
+                        points.push(egui::pos2(x, last_y));
+                    }
+                }
+                points.push(egui::pos2(x, y));
+            }
+            if let Some(&last) = points.last() {
+                points.push(egui::pos2(rect.right(), last.y));
+            }
+
+            painter.add(egui::Shape::line(points, egui::Stroke::new(1.5, egui::Color32::LIGHT_GREEN)));
+        }
+    });
+}
+
+const SAVE_PATH: &str = "circuit.json";
+
+/// Combinational sub-networks are relaxed to a fixed point within a single
+/// tick before giving up and flagging the offenders as oscillating.
+const MAX_SETTLE_ITERATIONS: u32 = 100;
+
+/// Default number of ticks of waveform history kept per probe.
+const DEFAULT_HISTORY_DEPTH: usize = 256;
+
 #[derive(Default, Resource)]
 struct Graph {
     state: Snarl<Node>,
+    /// Nodes that failed to settle to a stable value within
+    /// [`MAX_SETTLE_ITERATIONS`] on the last tick. Only tracked by
+    /// [`RunnerKind::Synchronous`]; the other runners leave this empty.
+    oscillating: HashSet<NodeId>,
+    /// Chips packaged with "Create chip from selection", available to drop
+    /// onto the canvas again from the graph menu.
+    chips: Vec<SubCircuit>,
+    /// Nodes checked in the Controls window's Selection list, used as the
+    /// input to "Create chip from selection".
+    selection: HashSet<NodeId>,
+    /// Name given to the next chip packaged via "Create chip from
+    /// selection", editable in the Controls window's Selection list.
+    chip_name: String,
+    /// Findings from [`diagnose`], refreshed every frame in `ui`.
+    diagnostics: Vec<Diagnostic>,
+    /// Evaluation strategy used by `tick`, selectable in the Controls window.
+    runner: RunnerKind,
+    /// Cached evaluation order for [`RunnerKind::Layered`]/
+    /// [`RunnerKind::EventDriven`], rebuilt by [`Graph::ensure_schedule`]
+    /// whenever the nodes or wiring change.
+    schedule: Option<CompiledSchedule>,
+}
+
+/// Evaluation strategy selectable in the Controls window. All three agree on
+/// purely combinational circuits; they differ in how much of the graph gets
+/// walked (and how much of that walk is cached) on each tick.
+#[derive(Clone, Copy, PartialEq, Eq, Default, strum::Display, strum::EnumIter)]
+enum RunnerKind {
+    /// `step` followed by an iterate-to-fixed-point `settle`, with
+    /// oscillation detection. Re-walks the whole combinational part of the
+    /// graph every tick.
+    #[default]
+    Synchronous,
+    /// `step` followed by a single pass over a cached topological order, so a
+    /// combinational chain converges in one pass instead of up to
+    /// [`MAX_SETTLE_ITERATIONS`]. Does not detect oscillation; feedback-loop
+    /// nodes ([`CompiledSchedule::cyclic`]) are left on `step`'s unit-delay
+    /// value for the tick, same as [`RunnerKind::EventDriven`].
+    Layered,
+    /// Like `Layered`, but also skips any node that isn't downstream of
+    /// something that actually changed value this tick.
+    EventDriven,
 }
 
-struct GraphViewer;
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+#[derive(Clone)]
+struct Diagnostic {
+    node: NodeId,
+    severity: Severity,
+    message: String,
+}
+
+/// Scans the graph for wiring mistakes: floating inputs, undriven outputs,
+/// inputs with more than one driver, and combinational cycles that failed to
+/// settle (from `oscillating`, as computed by [`settle`]).
+fn diagnose(snarl: &Snarl<Node>, oscillating: &HashSet<NodeId>) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+
+    for (id, node) in snarl.node_ids() {
+        for input in 0..node.input_count() {
+            let remotes = &snarl.in_pin(InPinId { node: id, input }).remotes;
+            if remotes.is_empty() {
+                let message = if matches!(node, Node::Output(_)) {
+                    "Output has nothing driving it".to_string()
+                } else {
+                    format!("Input {input} is floating")
+                };
+                diagnostics.push(Diagnostic {
+                    node: id,
+                    severity: Severity::Warning,
+                    message,
+                });
+            } else if remotes.len() > 1 {
+                diagnostics.push(Diagnostic {
+                    node: id,
+                    severity: Severity::Info,
+                    message: format!("Input {input} has {} drivers", remotes.len()),
+                });
+            }
+        }
+
+        if oscillating.contains(&id) {
+            diagnostics.push(Diagnostic {
+                node: id,
+                severity: Severity::Error,
+                message: "Combinational cycle did not settle".to_string(),
+            });
+        }
+    }
+
+    diagnostics
+}
+
+struct GraphViewer<'a> {
+    chips: &'a mut Vec<SubCircuit>,
+    selection: &'a mut HashSet<NodeId>,
+    chip_name: &'a str,
+}
 
 #[derive(Resource)]
 struct Simulation {
     timer: Timer,
     ticks: u64,
+    /// Nodes whose value is recorded into `history` every tick.
+    probes: HashSet<NodeId>,
+    /// Ring buffer (oldest first, capped at `history_depth`) of each probed
+    /// node's value, one sample per tick.
+    history: HashMap<NodeId, VecDeque<bool>>,
+    history_depth: usize,
+    /// Waveform view controls: pixels per sample, and how many samples back
+    /// from the latest to start drawing from.
+    zoom: f32,
+    scroll: usize,
 }
 
-#[derive(strum::Display, strum::EnumIter)]
+#[derive(Clone, strum::Display, serde::Serialize, serde::Deserialize)]
 enum Node {
     Input(bool),
     Output(bool),
@@ -105,6 +425,167 @@ enum Node {
     Xor(bool),
     Nor(bool),
     Xnor(bool),
+    SubCircuit(SubCircuit),
+}
+
+/// A reusable "chip": a packaged sub-graph whose unconnected pins are
+/// exposed as the chip's own inputs/outputs.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+struct SubCircuit {
+    name: String,
+    graph: Box<Snarl<Node>>,
+    /// External input index -> the internal pin it drives.
+    inputs: Vec<InPinId>,
+    /// External output index -> the internal pin it reads.
+    outputs: Vec<OutPinId>,
+    /// Last-computed value of each output pin, refreshed every step.
+    #[serde(skip)]
+    output_cache: Vec<bool>,
+    #[serde(skip)]
+    oscillating: HashSet<NodeId>,
+}
+
+impl SubCircuit {
+    /// Pulls `selection` out of `snarl` into a new chip, preserving the
+    /// wires between selected nodes and exposing every pin that's left
+    /// unconnected (whether it always was, or lost its remote because that
+    /// remote wasn't part of the selection) as a boundary pin.
+    ///
+    /// Also returns, for each boundary pin that used to carry a wire to a
+    /// node *outside* the selection, the other end of that wire: the caller
+    /// is responsible for reconnecting these to the new `SubCircuit`
+    /// instance once it has a `NodeId` in `snarl`, or the selection would
+    /// silently come out disconnected from the rest of the design.
+    fn from_selection(
+        snarl: &mut Snarl<Node>,
+        selection: &HashSet<NodeId>,
+        name: String,
+    ) -> (SubCircuit, Vec<(usize, OutPinId)>, Vec<(usize, InPinId)>) {
+        let incoming_wires = selection
+            .iter()
+            .map(|&id| {
+                let input_count = snarl.get_node(id).unwrap().input_count();
+                let remotes = (0..input_count)
+                    .map(|input| snarl.in_pin(InPinId { node: id, input }).remotes.clone())
+                    .collect::<Vec<_>>();
+                (id, remotes)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let outgoing_wires = selection
+            .iter()
+            .map(|&id| {
+                let output_count = snarl.get_node(id).unwrap().output_count();
+                let remotes = (0..output_count)
+                    .map(|output| snarl.out_pin(OutPinId { node: id, output }).remotes.clone())
+                    .collect::<Vec<_>>();
+                (id, remotes)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut inner = Snarl::<Node>::default();
+        let mut remap = HashMap::new();
+        for &old_id in selection {
+            let node = snarl.remove_node(old_id);
+            remap.insert(old_id, inner.insert_node(Pos2::ZERO, node));
+        }
+        let old_id_of = remap.iter().map(|(&old, &new)| (new, old)).collect::<HashMap<_, _>>();
+
+        for (&old_id, remotes_per_input) in &incoming_wires {
+            let new_id = remap[&old_id];
+            for (input, remotes) in remotes_per_input.iter().enumerate() {
+                for remote in remotes {
+                    if let Some(&new_remote) = remap.get(&remote.node) {
+                        inner.connect(
+                            OutPinId {
+                                node: new_remote,
+                                output: remote.output,
+                            },
+                            InPinId {
+                                node: new_id,
+                                input,
+                            },
+                        );
+                    }
+                }
+            }
+        }
+
+        let mut inputs = Vec::new();
+        let mut outputs = Vec::new();
+        for (id, node) in inner.node_ids() {
+            for input in 0..node.input_count() {
+                let pin = InPinId { node: id, input };
+                if inner.in_pin(pin).remotes.is_empty() {
+                    inputs.push(pin);
+                }
+            }
+            for output in 0..node.output_count() {
+                let pin = OutPinId { node: id, output };
+                if inner.out_pin(pin).remotes.is_empty() {
+                    outputs.push(pin);
+                }
+            }
+        }
+
+        let external_inputs = inputs
+            .iter()
+            .enumerate()
+            .filter_map(|(index, pin)| {
+                let old_id = old_id_of[&pin.node];
+                incoming_wires[&old_id][pin.input]
+                    .iter()
+                    .find(|remote| !remap.contains_key(&remote.node))
+                    .map(|&remote| (index, remote))
+            })
+            .collect::<Vec<_>>();
+
+        let external_outputs = outputs
+            .iter()
+            .enumerate()
+            .flat_map(|(index, pin)| {
+                let old_id = old_id_of[&pin.node];
+                outgoing_wires[&old_id][pin.output]
+                    .iter()
+                    .filter(|remote| !remap.contains_key(&remote.node))
+                    .map(move |&remote| (index, remote))
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        (
+            SubCircuit {
+                name,
+                graph: Box::new(inner),
+                inputs,
+                outputs,
+                output_cache: Vec::new(),
+                oscillating: HashSet::new(),
+            },
+            external_inputs,
+            external_outputs,
+        )
+    }
+
+    /// Drives the chip's boundary inputs with `inputs`, advances the inner
+    /// graph by one tick, and refreshes the cached boundary output values.
+    fn step(&mut self, inputs: &[bool]) {
+        let overrides = self
+            .inputs
+            .iter()
+            .copied()
+            .zip(inputs.iter().copied())
+            .collect::<HashMap<_, _>>();
+
+        step(&mut self.graph, &overrides);
+        self.oscillating = settle(&mut self.graph, &overrides);
+
+        self.output_cache = self
+            .outputs
+            .iter()
+            .map(|&pin| output_at(&self.graph, pin.node, pin.output))
+            .collect();
+    }
 }
 
 impl Node {
@@ -121,6 +602,7 @@ impl Node {
             Node::Xor(_) => 2,
             Node::Nor(_) => 2,
             Node::Xnor(_) => 2,
+            Node::SubCircuit(sub) => sub.inputs.len(),
         }
     }
 
@@ -137,6 +619,7 @@ impl Node {
             Node::Xor(_) => 1,
             Node::Nor(_) => 1,
             Node::Xnor(_) => 1,
+            Node::SubCircuit(sub) => sub.outputs.len(),
         }
     }
 
@@ -153,9 +636,25 @@ impl Node {
             Node::Xor(_) => false,
             Node::Nor(_) => false,
             Node::Xnor(_) => false,
+            Node::SubCircuit(_) => false,
         }
     }
 
+    /// The name shown as the node's title: the chip's own name for
+    /// [`Node::SubCircuit`], the variant name for everything else.
+    fn display_name(&self) -> String {
+        match self {
+            Node::SubCircuit(sub) => sub.name.clone(),
+            node => node.to_string(),
+        }
+    }
+
+    /// Whether this node is a meaningful single-bit signal source/sink that
+    /// the waveform panel can probe.
+    fn is_probeable(&self) -> bool {
+        matches!(self, Node::Input(_) | Node::Output(_) | Node::Clock(_))
+    }
+
     fn show_body(&mut self, ui: &mut egui::Ui) {
         match self {
             Node::Input(value) => {
@@ -173,9 +672,64 @@ impl Node {
             Node::Xor(_) => unreachable!(),
             Node::Nor(_) => unreachable!(),
             Node::Xnor(_) => unreachable!(),
+            Node::SubCircuit(_) => unreachable!(),
+        }
+    }
+
+    /// The node's stored output bit, as computed on the previous tick.
+    /// [`Node::SubCircuit`] has no single output bit; see [`output_at`].
+    fn output(&self) -> bool {
+        match self {
+            Node::Input(value)
+            | Node::Output(value)
+            | Node::Nand(value)
+            | Node::Clock(value)
+            | Node::Node(value)
+            | Node::Not(value)
+            | Node::And(value)
+            | Node::Or(value)
+            | Node::Xor(value)
+            | Node::Nor(value)
+            | Node::Xnor(value) => *value,
+            Node::SubCircuit(_) => unreachable!("sub-circuits have no single stored output bit"),
+        }
+    }
+
+    fn set_output(&mut self, value: bool) {
+        match self {
+            Node::Input(stored)
+            | Node::Output(stored)
+            | Node::Nand(stored)
+            | Node::Clock(stored)
+            | Node::Node(stored)
+            | Node::Not(stored)
+            | Node::And(stored)
+            | Node::Or(stored)
+            | Node::Xor(stored)
+            | Node::Nor(stored)
+            | Node::Xnor(stored) => *stored = value,
+            Node::SubCircuit(_) => unreachable!("sub-circuits have no single stored output bit"),
         }
     }
 
+    /// The built-in gate/io variants offered by the graph menu. Chips are
+    /// offered separately, from [`Graph::chips`].
+    fn base_variants() -> [Node; 11] {
+        [
+            Node::Input(false),
+            Node::Output(false),
+            Node::Nand(false),
+            Node::Clock(false),
+            Node::Node(false),
+            Node::Not(false),
+            Node::And(false),
+            Node::Or(false),
+            Node::Xor(false),
+            Node::Nor(false),
+            Node::Xnor(false),
+        ]
+    }
+
     fn graph_menu_item(self, ui: &mut egui::Ui, snarl: &mut Snarl<Node>, pos: Pos2) {
         if ui.button(format!("Add {}", self)).clicked() {
             ui.close_menu();
@@ -188,61 +742,317 @@ impl Node {
     }
 }
 
-impl Graph {
-    #[recursive]
-    fn eval(
-        &mut self,
-        in_pin: InPinId,
-        ticks: u64,
-        cache: &mut HashMap<(InPinId, u64), bool>,
-    ) -> bool {
-        if let Some(value) = cache.get(&(in_pin, ticks)) {
-            return *value;
+/// `node`'s `output`-th stored output. [`Node::SubCircuit`] recurses into its
+/// cached boundary outputs instead of a single stored bit.
+fn output_at(snarl: &Snarl<Node>, node: NodeId, output: usize) -> bool {
+    match snarl.get_node(node).unwrap() {
+        Node::SubCircuit(sub) => sub.output_cache.get(output).copied().unwrap_or(false),
+        node => node.output(),
+    }
+}
+
+/// The value currently driving `node`'s `input`-th pin: the OR of the stored
+/// outputs of whatever is wired into it, or `false` if nothing is. `overrides`
+/// lets a chip's inner graph be driven by its external callers instead of its
+/// (necessarily empty) internal wiring on its boundary pins.
+fn driven_value(
+    snarl: &Snarl<Node>,
+    overrides: &HashMap<InPinId, bool>,
+    node: NodeId,
+    input: usize,
+) -> bool {
+    let pin = InPinId { node, input };
+    if let Some(&value) = overrides.get(&pin) {
+        return value;
+    }
+    snarl
+        .in_pin(pin)
+        .remotes
+        .iter()
+        .any(|remote| output_at(snarl, remote.node, remote.output))
+}
+
+/// Computes `id`'s next stored output purely from the *previous* tick's
+/// stored outputs of whatever drives it.
+fn next_value(snarl: &Snarl<Node>, overrides: &HashMap<InPinId, bool>, id: NodeId, node: &Node) -> bool {
+    let driven = |input| driven_value(snarl, overrides, id, input);
+    match node {
+        Node::Input(value) => *value,
+        Node::Output(_) => driven(0),
+        Node::Nand(_) => !(driven(0) & driven(1)),
+        Node::Clock(value) => !value,
+        Node::Node(_) => driven(0),
+        Node::Not(_) => !driven(0),
+        Node::And(_) => driven(0) & driven(1),
+        Node::Or(_) => driven(0) | driven(1),
+        Node::Xor(_) => driven(0) ^ driven(1),
+        Node::Nor(_) => !(driven(0) | driven(1)),
+        Node::Xnor(_) => !(driven(0) ^ driven(1)),
+        Node::SubCircuit(_) => unreachable!("sub-circuits are stepped via SubCircuit::step"),
+    }
+}
+
+/// Advances every node by one unit of delay: each node's next output is
+/// computed from last tick's stored outputs and all next-values are
+/// committed simultaneously. This is what gives a NAND-pair latch (or any
+/// feedback loop) well-defined, non-recursive semantics: a cycle just settles
+/// over a couple of ticks instead of infinitely recursing. Sub-circuits are
+/// stepped separately, since they have no single stored output bit, only a
+/// cache of their many boundary outputs.
+fn step(snarl: &mut Snarl<Node>, overrides: &HashMap<InPinId, bool>) {
+    let ids = snarl.node_ids().map(|(id, _)| id).collect::<Vec<_>>();
+
+    let sub_inputs = ids
+        .iter()
+        .filter_map(|&id| match snarl.get_node(id).unwrap() {
+            Node::SubCircuit(sub) => Some((
+                id,
+                (0..sub.inputs.len())
+                    .map(|input| driven_value(snarl, overrides, id, input))
+                    .collect::<Vec<_>>(),
+            )),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+
+    let next = ids
+        .iter()
+        .filter_map(|&id| match snarl.get_node(id).unwrap() {
+            Node::SubCircuit(_) => None,
+            node => Some((id, next_value(snarl, overrides, id, node))),
+        })
+        .collect::<Vec<_>>();
+
+    for (id, value) in next {
+        snarl.get_node_mut(id).unwrap().set_output(value);
+    }
+
+    for (id, inputs) in sub_inputs {
+        if let Node::SubCircuit(sub) = snarl.get_node_mut(id).unwrap() {
+            sub.step(&inputs);
         }
+    }
+}
 
-        let result = self.state.in_pin(in_pin).remotes.iter().any(|remote| {
-            let node = remote.node;
-            match self.state.get_node(remote.node).unwrap() {
-                Node::Input(value) => *value,
-                Node::Nand(_) => {
-                    let a = self.eval(InPinId { node, input: 0 }, ticks, cache);
-                    let b = self.eval(InPinId { node, input: 1 }, ticks, cache);
-                    !(a & b)
-                }
-                Node::Output(_) => unreachable!("Outputs should only be connected to inputs"),
-                Node::Clock(_) => ticks % 2 == 0,
-                Node::Node(_) => self.eval(InPinId { node, input: 0 }, ticks, cache),
-                Node::Not(_) => !self.eval(InPinId { node, input: 0 }, ticks, cache),
-                Node::And(_) => {
-                    let a = self.eval(InPinId { node, input: 0 }, ticks, cache);
-                    let b = self.eval(InPinId { node, input: 1 }, ticks, cache);
-                    a & b
-                }
-                Node::Or(_) => {
-                    let a = self.eval(InPinId { node, input: 0 }, ticks, cache);
-                    let b = self.eval(InPinId { node, input: 1 }, ticks, cache);
-                    a | b
-                }
-                Node::Xor(_) => {
-                    let a = self.eval(InPinId { node, input: 0 }, ticks, cache);
-                    let b = self.eval(InPinId { node, input: 1 }, ticks, cache);
-                    a ^ b
-                }
-                Node::Nor(_) => {
-                    let a = self.eval(InPinId { node, input: 0 }, ticks, cache);
-                    let b = self.eval(InPinId { node, input: 1 }, ticks, cache);
-                    !(a | b)
-                }
-                Node::Xnor(_) => {
-                    let a = self.eval(InPinId { node, input: 0 }, ticks, cache);
-                    let b = self.eval(InPinId { node, input: 1 }, ticks, cache);
-                    !(a ^ b)
-                }
+/// Relaxes the purely combinational part of the graph (everything except
+/// `Input`/`Clock`/`SubCircuit`, which only change once per tick in
+/// [`step`]) to a fixed point, so combinational chains don't have to wait
+/// multiple ticks to propagate. Gives up after [`MAX_SETTLE_ITERATIONS`] and
+/// returns whatever is still changing as oscillating.
+fn settle(snarl: &mut Snarl<Node>, overrides: &HashMap<InPinId, bool>) -> HashSet<NodeId> {
+    let ids = snarl
+        .node_ids()
+        .filter(|(_, node)| !matches!(node, Node::Input(_) | Node::Clock(_) | Node::SubCircuit(_)))
+        .map(|(id, _)| id)
+        .collect::<Vec<_>>();
+
+    let mut oscillating = HashSet::new();
+
+    for _ in 0..MAX_SETTLE_ITERATIONS {
+        let next = ids
+            .iter()
+            .map(|&id| (id, next_value(snarl, overrides, id, snarl.get_node(id).unwrap())))
+            .collect::<Vec<_>>();
+
+        let changed = next
+            .iter()
+            .filter(|&&(id, value)| snarl.get_node(id).unwrap().output() != value)
+            .map(|&(id, _)| id)
+            .collect::<HashSet<_>>();
+
+        for (id, value) in next {
+            snarl.get_node_mut(id).unwrap().set_output(value);
+        }
+
+        if changed.is_empty() {
+            return HashSet::new();
+        }
+
+        oscillating = changed;
+    }
+
+    oscillating
+}
+
+/// A cached evaluation order for [`RunnerKind::Layered`] and
+/// [`RunnerKind::EventDriven`], built by [`compile_schedule`].
+///
+/// `order` drives writes straight back through `snarl.get_node_mut(id)`
+/// rather than into a separate flat `Vec<bool>` indexed by node: `NodeId` is
+/// an opaque handle into `Snarl`'s own storage, not a dense index we can
+/// safely reuse, and `Snarl::get_node`/`get_node_mut` are already O(1), so a
+/// second flat array would only duplicate storage without avoiding any
+/// per-tick allocation. What actually eliminates the per-tick cost is
+/// caching `order`/`cyclic`/`dependents` themselves across ticks.
+struct CompiledSchedule {
+    /// Commutative hash of every node and wire, so [`Graph::ensure_schedule`] can tell
+    /// the schedule is stale without re-walking the whole graph every tick.
+    fingerprint: u64,
+    /// Topological order of the acyclic part (via Kahn's algorithm), with
+    /// any nodes left over from an unbroken feedback loop appended at the
+    /// end.
+    order: Vec<NodeId>,
+    /// The leftover nodes mentioned above: part of a cycle or downstream of
+    /// one, so they keep plain unit-delay semantics instead of being read in
+    /// dependency order.
+    cyclic: HashSet<NodeId>,
+    /// node -> the nodes whose inputs it drives, used to expand a dirty set
+    /// downstream in [`run_event_driven`].
+    dependents: HashMap<NodeId, Vec<NodeId>>,
+}
+
+/// Commutative over nodes and wires, so it doesn't depend on `Snarl`'s
+/// iteration order: changes when a node is added or removed, or a wire is
+/// added, removed, or rerouted.
+fn topology_fingerprint(snarl: &Snarl<Node>) -> u64 {
+    let mut fingerprint: u64 = 0;
+    for (id, node) in snarl.node_ids() {
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        id.hash(&mut hasher);
+        fingerprint ^= hasher.finish();
+        for input in 0..node.input_count() {
+            for remote in &snarl.in_pin(InPinId { node: id, input }).remotes {
+                let mut hasher = std::collections::hash_map::DefaultHasher::new();
+                (id, input, remote.node, remote.output).hash(&mut hasher);
+                fingerprint ^= hasher.finish();
             }
-        });
+        }
+    }
+    fingerprint
+}
+
+/// Topologically sorts the graph via Kahn's algorithm: repeatedly emits
+/// nodes whose drivers have all already been emitted. Nodes stuck in a
+/// feedback loop never reach in-degree zero, and neither does anything they
+/// drive, so all of those are appended afterwards
+/// and kept on unit-delay semantics instead (see [`CompiledSchedule::cyclic`]).
+fn compile_schedule(snarl: &Snarl<Node>) -> CompiledSchedule {
+    let ids = snarl.node_ids().map(|(id, _)| id).collect::<Vec<_>>();
+
+    let mut in_degree = HashMap::new();
+    let mut dependents: HashMap<NodeId, Vec<NodeId>> = HashMap::new();
+    for &id in &ids {
+        let node = snarl.get_node(id).unwrap();
+        let drivers = (0..node.input_count())
+            .flat_map(|input| snarl.in_pin(InPinId { node: id, input }).remotes.clone())
+            .map(|remote| remote.node)
+            .collect::<HashSet<_>>();
+        in_degree.insert(id, drivers.len());
+        for driver in drivers {
+            dependents.entry(driver).or_default().push(id);
+        }
+    }
+
+    let mut remaining = in_degree.clone();
+    let mut ready = in_degree
+        .iter()
+        .filter(|&(_, &degree)| degree == 0)
+        .map(|(&id, _)| id)
+        .collect::<Vec<_>>();
+
+    let mut order = Vec::with_capacity(ids.len());
+    while let Some(id) = ready.pop() {
+        order.push(id);
+        for &dependent in dependents.get(&id).into_iter().flatten() {
+            let degree = remaining.get_mut(&dependent).unwrap();
+            *degree -= 1;
+            if *degree == 0 {
+                ready.push(dependent);
+            }
+        }
+    }
+
+    let scheduled = order.iter().copied().collect::<HashSet<_>>();
+    let cyclic = ids
+        .into_iter()
+        .filter(|id| !scheduled.contains(id))
+        .collect::<HashSet<_>>();
+    order.extend(cyclic.iter().copied());
 
-        cache.insert((in_pin, ticks), result);
-        result
+    CompiledSchedule {
+        fingerprint: topology_fingerprint(snarl),
+        order,
+        cyclic,
+        dependents,
+    }
+}
+
+/// Only re-evaluates what's downstream of a node that actually changed value
+/// this tick, by expanding a dirty set along `schedule.dependents` and then
+/// filtering `schedule.order` down to it. The seed is every node's output
+/// before vs. after `step` (not just `Input`/`Clock`): `step` also advances
+/// every combinational node by one unit-delay pass, so a gate can change
+/// value with its own drivers unchanged since last tick, and missing it here
+/// would leave its downstream stuck for the rest of this tick. Sub-circuits
+/// are always treated as dirty, since diffing their whole boundary output
+/// vector isn't worth the bookkeeping.
+fn run_event_driven(snarl: &mut Snarl<Node>, schedule: &CompiledSchedule) {
+    let before = schedule
+        .order
+        .iter()
+        .copied()
+        .filter(|&id| !matches!(snarl.get_node(id).unwrap(), Node::SubCircuit(_)))
+        .map(|id| (id, snarl.get_node(id).unwrap().output()))
+        .collect::<Vec<_>>();
+
+    step(snarl, &HashMap::new());
+
+    let mut dirty = before
+        .into_iter()
+        .filter(|&(id, was)| snarl.get_node(id).unwrap().output() != was)
+        .map(|(id, _)| id)
+        .chain(
+            schedule
+                .order
+                .iter()
+                .copied()
+                .filter(|&id| matches!(snarl.get_node(id).unwrap(), Node::SubCircuit(_))),
+        )
+        .collect::<HashSet<_>>();
+
+    let mut queue = dirty.iter().copied().collect::<Vec<_>>();
+    while let Some(id) = queue.pop() {
+        for &dependent in schedule.dependents.get(&id).into_iter().flatten() {
+            if dirty.insert(dependent) {
+                queue.push(dependent);
+            }
+        }
+    }
+
+    for &id in &schedule.order {
+        if schedule.cyclic.contains(&id) {
+            continue;
+        }
+        let node = snarl.get_node(id).unwrap();
+        if matches!(node, Node::Input(_) | Node::Clock(_) | Node::SubCircuit(_)) || !dirty.contains(&id) {
+            continue;
+        }
+        let value = next_value(snarl, &HashMap::new(), id, node);
+        snarl.get_node_mut(id).unwrap().set_output(value);
+    }
+}
+
+impl Graph {
+    fn save(&self, path: &str) {
+        let Ok(json) = serde_json::to_string_pretty(&self.state) else {
+            return;
+        };
+        let _ = std::fs::write(path, json);
+    }
+
+    fn load(&mut self, path: &str) {
+        let Ok(json) = std::fs::read_to_string(path) else {
+            return;
+        };
+        if let Ok(state) = serde_json::from_str(&json) {
+            self.state = state;
+            // These all reference NodeIds from the graph `state` just
+            // replaced; left stale, a smaller loaded circuit can leave
+            // `selection` pointing at ids that no longer exist.
+            self.selection.clear();
+            self.oscillating.clear();
+            self.schedule = None;
+        }
     }
 
     fn tick(&mut self, simulation: &mut Simulation, dt: Duration) {
@@ -251,30 +1061,76 @@ impl Graph {
         if dt == Duration::ZERO || simulation.timer.finished() {
             simulation.ticks += 1;
 
-            let outputs = self
-                .state
-                .node_ids()
-                .filter_map(|(id, node)| match node {
-                    Node::Output(_) => Some(id),
-                    _ => None,
-                })
-                .collect::<Vec<_>>();
+            match self.runner {
+                RunnerKind::Synchronous => {
+                    step(&mut self.state, &HashMap::new());
+                    self.oscillating = settle(&mut self.state, &HashMap::new());
+                }
+                RunnerKind::Layered => {
+                    self.ensure_schedule();
+                    step(&mut self.state, &HashMap::new());
+                    let schedule = self.schedule.as_ref().unwrap();
+                    for &id in &schedule.order {
+                        if schedule.cyclic.contains(&id) {
+                            continue;
+                        }
+                        let node = self.state.get_node(id).unwrap();
+                        if matches!(node, Node::Input(_) | Node::Clock(_) | Node::SubCircuit(_)) {
+                            continue;
+                        }
+                        let value = next_value(&self.state, &HashMap::new(), id, node);
+                        self.state.get_node_mut(id).unwrap().set_output(value);
+                    }
+                    self.oscillating.clear();
+                }
+                RunnerKind::EventDriven => {
+                    self.ensure_schedule();
+                    let schedule = self.schedule.as_ref().unwrap();
+                    run_event_driven(&mut self.state, schedule);
+                    self.oscillating.clear();
+                }
+            }
 
-            let mut cache = HashMap::new();
+            self.record_history(simulation);
+        }
+    }
 
-            for node in outputs {
-                let result = self.eval(InPinId { node, input: 0 }, simulation.ticks, &mut cache);
-                if let Node::Output(value) = self.state.get_node_mut(node).unwrap() {
-                    *value = result;
-                }
+    /// Rebuilds the cached schedule iff the nodes or wiring have changed since
+    /// it was last compiled (or it's never been compiled).
+    fn ensure_schedule(&mut self) {
+        let fingerprint = topology_fingerprint(&self.state);
+        let stale = match &self.schedule {
+            Some(schedule) => schedule.fingerprint != fingerprint,
+            None => true,
+        };
+        if stale {
+            self.schedule = Some(compile_schedule(&self.state));
+        }
+    }
+
+    /// Appends this tick's value of every probed node to its history,
+    /// dropping samples older than `history_depth`.
+    fn record_history(&self, simulation: &mut Simulation) {
+        for &id in &simulation.probes {
+            let value = self
+                .state
+                .get_node(id)
+                .filter(|node| node.is_probeable())
+                .map(Node::output)
+                .unwrap_or(false);
+
+            let history = simulation.history.entry(id).or_default();
+            history.push_back(value);
+            while history.len() > simulation.history_depth {
+                history.pop_front();
             }
         }
     }
 }
 
-impl SnarlViewer<Node> for GraphViewer {
+impl SnarlViewer<Node> for GraphViewer<'_> {
     fn title(&mut self, node: &Node) -> String {
-        node.to_string()
+        node.display_name()
     }
 
     fn outputs(&mut self, node: &Node) -> usize {
@@ -285,6 +1141,24 @@ impl SnarlViewer<Node> for GraphViewer {
         node.input_count()
     }
 
+    /// Draws a highlight ring around nodes clicking a [`Diagnostic`] put in
+    /// `selection`, so "focus the offending node" is an actual on-canvas
+    /// effect and not just a change to a `HashSet` nothing else reads.
+    fn final_node_rect(
+        &mut self,
+        node: NodeId,
+        ui_rect: egui::Rect,
+        _graph_rect: egui::Rect,
+        ui: &mut egui::Ui,
+        _scale: f32,
+        _snarl: &mut Snarl<Node>,
+    ) {
+        if self.selection.contains(&node) {
+            ui.painter()
+                .rect_stroke(ui_rect.expand(3.0), 4.0, egui::Stroke::new(2.0, egui::Color32::YELLOW));
+        }
+    }
+
     fn show_input(
         &mut self,
         _pin: &egui_snarl::InPin,
@@ -316,7 +1190,44 @@ impl SnarlViewer<Node> for GraphViewer {
         _scale: f32,
         snarl: &mut Snarl<Node>,
     ) {
-        Node::iter().for_each(|value| value.graph_menu_item(ui, snarl, pos));
+        Node::base_variants()
+            .into_iter()
+            .for_each(|value| value.graph_menu_item(ui, snarl, pos));
+
+        if !self.selection.is_empty() && ui.button("Create chip from selection").clicked() {
+            ui.close_menu();
+            let name = if self.chip_name.trim().is_empty() {
+                "Chip".to_string()
+            } else {
+                self.chip_name.to_string()
+            };
+            let (chip, external_inputs, external_outputs) =
+                SubCircuit::from_selection(snarl, self.selection, name);
+            let instance = snarl.insert_node(pos, Node::SubCircuit(chip.clone()));
+            for (input, remote) in external_inputs {
+                snarl.connect(remote, InPinId { node: instance, input });
+            }
+            for (output, remote) in external_outputs {
+                snarl.connect(OutPinId { node: instance, output }, remote);
+            }
+            // The selected NodeIds no longer exist in `snarl`; leaving them
+            // in `selection` would panic the next "Create chip from
+            // selection" click. Point the selection at the new instance
+            // instead of just clearing it, so it stays usable right away.
+            self.selection.clear();
+            self.selection.insert(instance);
+            self.chips.push(chip);
+        }
+
+        if !self.chips.is_empty() {
+            ui.separator();
+            for chip in self.chips.iter() {
+                if ui.button(format!("Add {}", chip.name)).clicked() {
+                    ui.close_menu();
+                    snarl.insert_node(pos, Node::SubCircuit(chip.clone()));
+                }
+            }
+        }
     }
 
     fn has_body(&mut self, node: &Node) -> bool {